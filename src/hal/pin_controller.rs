@@ -21,9 +21,12 @@ use super::NodeId;
 use super::UsbMode;
 use super::UsbRoute;
 use anyhow::Context;
-use gpiod::{Chip, Lines, Output};
-use log::debug;
+use gpiod::{Chip, EdgeDetect, Input, Lines, Output};
+use log::{debug, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
 use std::time::Duration;
+use tokio::sync::{watch, Mutex};
 use tokio::time::sleep;
 
 const USB_PORT_POWER: &str = "/sys/bus/platform/devices/usb-port-power/state";
@@ -38,12 +41,113 @@ const NODE2_RPIBOOT: &str = "node2-rpiboot";
 const NODE3_RPIBOOT: &str = "node3-rpiboot";
 const NODE4_RPIBOOT: &str = "node4-rpiboot";
 
+const NODE1_USB_VBUS_DET: &str = "node1-usb-vbus-det";
+const NODE2_USB_VBUS_DET: &str = "node2-usb-vbus-det";
+const NODE3_USB_VBUS_DET: &str = "node3-usb-vbus-det";
+const NODE4_USB_VBUS_DET: &str = "node4-usb-vbus-det";
+
+const NODE1_USB_ID_DET: &str = "node1-usb-id-det";
+const NODE2_USB_ID_DET: &str = "node2-usb-id-det";
+const NODE3_USB_ID_DET: &str = "node3-usb-id-det";
+const NODE4_USB_ID_DET: &str = "node4-usb-id-det";
+
+/// Debounce window applied to a VBUS/ID transition before it is trusted.
+const USB_ROLE_DEBOUNCE: Duration = Duration::from_millis(100);
+/// Interval at which the role-detection task re-samples the sense lines even without an edge
+/// event. When the gpiod backend cannot deliver line edge events, this is the only wake-up.
+const USB_ROLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Delays observed by [`PinController`]'s glitch-free USB switching sequence: VBUS is dropped,
+/// the change is given time to settle, the mux/route is reprogrammed, and another settle window
+/// is observed before VBUS is restored. The defaults are generous enough for the onboard FETs
+/// and analog switches to fully settle without making routine mode switches feel sluggish.
+#[derive(Debug, Clone, Copy)]
+pub struct UsbSwitchTiming {
+    /// Time to wait after VBUS is dropped before touching the mux/route switches.
+    pub vbus_settle: Duration,
+    /// Time to wait after the mux/route switches before re-enabling VBUS.
+    pub mux_settle: Duration,
+}
+
+impl Default for UsbSwitchTiming {
+    fn default() -> Self {
+        Self {
+            vbus_settle: Duration::from_millis(30),
+            mux_settle: Duration::from_millis(30),
+        }
+    }
+}
+
+/// Delays observed by [`PinController::hub_power_cycle`] when recovering the onboard USB hub.
+#[derive(Debug, Clone, Copy)]
+pub struct HubPowerCycleTiming {
+    /// How long `RTL_RESET` is held asserted.
+    pub reset_hold: Duration,
+    /// How long to wait after releasing reset before downstream power is restored, giving the
+    /// hub time to re-enumerate.
+    pub reenumerate_settle: Duration,
+    /// Delay between restoring downstream power to successive nodes.
+    pub port_power_stagger: Duration,
+}
+
+impl Default for HubPowerCycleTiming {
+    fn default() -> Self {
+        Self {
+            reset_hold: Duration::from_secs(1),
+            reenumerate_settle: Duration::from_millis(500),
+            port_power_stagger: Duration::from_millis(20),
+        }
+    }
+}
+
+/// Cable role inferred from VBUS-valid and ID/session pin sensing, mirroring the state machine
+/// an OTG PHY would run: ID grounded selects host mode, VBUS present with ID floating selects
+/// peripheral mode, anything else means nothing is plugged in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbRole {
+    Disconnected,
+    Host,
+    Peripheral,
+}
+
+impl UsbRole {
+    fn from_sense(vbus_valid: bool, id_grounded: bool) -> Self {
+        match (vbus_valid, id_grounded) {
+            (_, true) => UsbRole::Host,
+            (true, false) => UsbRole::Peripheral,
+            (false, false) => UsbRole::Disconnected,
+        }
+    }
+
+    fn to_usb_mode(self) -> Option<UsbMode> {
+        match self {
+            UsbRole::Host => Some(UsbMode::Host),
+            UsbRole::Peripheral => Some(UsbMode::Device),
+            UsbRole::Disconnected => None,
+        }
+    }
+}
+
 pub struct PinController {
     usb_vbus: Lines<Output>,
     usb_mux: Lines<Output>,
     usb_switch: Lines<Output>,
     rpi_boot: [Lines<Output>; 4],
     rtl_reset: Lines<Output>,
+    usb_vbus_det: Lines<Input>,
+    usb_id_det: Lines<Input>,
+    /// Set once [`Self::wait_for_edge`] has already warned about a given sense line not
+    /// supporting edge events, so the fallback poll loop doesn't re-log the same warning on
+    /// every tick for as long as the board runs.
+    usb_vbus_det_edge_warned: AtomicBool,
+    usb_id_det_edge_warned: AtomicBool,
+    /// The node currently selected in the mux, if any. Tracked so `set_usb_route()` knows which
+    /// node's VBUS line needs glitch protection while the route switch is reprogrammed, even
+    /// though it doesn't change which node is selected itself.
+    current_node: StdMutex<Option<NodeId>>,
+    /// Serializes manual `select_usb()` calls against the auto-detection task so the two never
+    /// race while reprogramming the mux, since only one node may be routed at a time.
+    usb_role_lock: Mutex<()>,
 }
 
 impl PinController {
@@ -89,31 +193,105 @@ impl PinController {
             .request_lines(gpiod::Options::output([RTL_RESET]).active(gpiod::Active::Low))
             .context(concat!("error initializing pin rtl reset"))?;
 
+        let vbus_det1 = *chip1_lines
+            .get(NODE1_USB_VBUS_DET)
+            .ok_or(anyhow::anyhow!("cannot find node1-usb-vbus-det gpio"))?;
+        let vbus_det2 = *chip1_lines
+            .get(NODE2_USB_VBUS_DET)
+            .ok_or(anyhow::anyhow!("cannot find node2-usb-vbus-det gpio"))?;
+        let vbus_det3 = *chip1_lines
+            .get(NODE3_USB_VBUS_DET)
+            .ok_or(anyhow::anyhow!("cannot find node3-usb-vbus-det gpio"))?;
+        let vbus_det4 = *chip1_lines
+            .get(NODE4_USB_VBUS_DET)
+            .ok_or(anyhow::anyhow!("cannot find node4-usb-vbus-det gpio"))?;
+
+        let usb_vbus_det = chip1
+            .request_lines(
+                gpiod::Options::input([vbus_det1, vbus_det2, vbus_det3, vbus_det4])
+                    .edge(EdgeDetect::Both)
+                    .consumer("bmcd-usb-vbus-det"),
+            )
+            .context("error initializing usb vbus-det lines")?;
+
+        let id_det1 = *chip1_lines
+            .get(NODE1_USB_ID_DET)
+            .ok_or(anyhow::anyhow!("cannot find node1-usb-id-det gpio"))?;
+        let id_det2 = *chip1_lines
+            .get(NODE2_USB_ID_DET)
+            .ok_or(anyhow::anyhow!("cannot find node2-usb-id-det gpio"))?;
+        let id_det3 = *chip1_lines
+            .get(NODE3_USB_ID_DET)
+            .ok_or(anyhow::anyhow!("cannot find node3-usb-id-det gpio"))?;
+        let id_det4 = *chip1_lines
+            .get(NODE4_USB_ID_DET)
+            .ok_or(anyhow::anyhow!("cannot find node4-usb-id-det gpio"))?;
+
+        let usb_id_det = chip1
+            .request_lines(
+                gpiod::Options::input([id_det1, id_det2, id_det3, id_det4])
+                    .edge(EdgeDetect::Both)
+                    .consumer("bmcd-usb-id-det"),
+            )
+            .context("error initializing usb id-det lines")?;
+
         Ok(Self {
             usb_vbus,
             usb_mux,
             usb_switch,
             rpi_boot,
             rtl_reset,
+            usb_vbus_det,
+            usb_id_det,
+            usb_vbus_det_edge_warned: AtomicBool::new(false),
+            usb_id_det_edge_warned: AtomicBool::new(false),
+            current_node: StdMutex::new(None),
+            usb_role_lock: Mutex::new(()),
         })
     }
 
-    /// Select which node is active in the multiplexer (see PORTx in `set_usb_route()`)
-    pub fn select_usb(&self, node: NodeId, mode: UsbMode) -> std::io::Result<()> {
+    /// Select which node is active in the multiplexer (see PORTx in `set_usb_route()`), using
+    /// the default [`UsbSwitchTiming`].
+    pub async fn select_usb(&self, node: NodeId, mode: UsbMode) -> std::io::Result<()> {
+        self.select_usb_with_timing(node, mode, UsbSwitchTiming::default())
+            .await
+    }
+
+    /// Like [`Self::select_usb`], but with explicit switching delays.
+    pub async fn select_usb_with_timing(
+        &self,
+        node: NodeId,
+        mode: UsbMode,
+        timing: UsbSwitchTiming,
+    ) -> std::io::Result<()> {
+        let _guard = self.usb_role_lock.lock().await;
+        self.select_usb_locked(node, mode, timing).await
+    }
+
+    /// Body of [`Self::select_usb_with_timing`], assuming `usb_role_lock` is already held. Lets
+    /// [`Self::select_usb_and_route_with_timing`] compose this with [`Self::set_usb_route_locked`]
+    /// under a single critical section.
+    async fn select_usb_locked(
+        &self,
+        node: NodeId,
+        mode: UsbMode,
+        timing: UsbSwitchTiming,
+    ) -> std::io::Result<()> {
         debug!("select USB for node {:?}, mode:{:?}", node, mode);
-        let values: u8 = match node {
+        let mux_values: u8 = match node {
             NodeId::Node1 => 0b1100,
             NodeId::Node2 => 0b1101,
             NodeId::Node3 => 0b0011,
             NodeId::Node4 => 0b0111,
         };
-        self.usb_mux.set_values(values)?;
 
         let vbus = match mode {
             UsbMode::Host => node.to_inverse_bitfield(),
             UsbMode::Device | UsbMode::Flash => 0b1111,
         };
-        self.usb_vbus.set_values(vbus)?;
+
+        self.switch_usb(0b1111, Some(mux_values), None, Some(vbus), timing)
+            .await?;
 
         if UsbMode::Flash == mode {
             self.set_usb_boot(node.to_bitfield(), node.to_bitfield())?;
@@ -121,23 +299,132 @@ impl PinController {
             self.set_usb_boot(0, 0b1111)?;
         }
 
+        *self.current_node.lock().unwrap() = Some(node);
+
         Ok(())
     }
 
     /// Set which way the USB is routed: USB-A ↔ PORTx (`UsbRoute::UsbA`) or BMC ↔ PORTx
-    /// (`UsbRoute::Bmc`)
+    /// (`UsbRoute::Bmc`), using the default [`UsbSwitchTiming`].
     pub async fn set_usb_route(&self, route: UsbRoute) -> std::io::Result<()> {
+        self.set_usb_route_with_timing(route, UsbSwitchTiming::default())
+            .await
+    }
+
+    /// Like [`Self::set_usb_route`], but with explicit switching delays.
+    pub async fn set_usb_route_with_timing(
+        &self,
+        route: UsbRoute,
+        timing: UsbSwitchTiming,
+    ) -> std::io::Result<()> {
+        let _guard = self.usb_role_lock.lock().await;
+        self.set_usb_route_locked(route, timing).await
+    }
+
+    /// Body of [`Self::set_usb_route_with_timing`], assuming `usb_role_lock` is already held.
+    /// Lets [`Self::select_usb_and_route_with_timing`] compose this with
+    /// [`Self::select_usb_locked`] under a single critical section.
+    async fn set_usb_route_locked(
+        &self,
+        route: UsbRoute,
+        timing: UsbSwitchTiming,
+    ) -> std::io::Result<()> {
         debug!("select USB route {:?}", route);
-        match route {
-            UsbRoute::UsbA => {
-                self.usb_switch.set_values(0_u8)?;
-                tokio::fs::write(USB_PORT_POWER, b"enabled").await
-            }
-            UsbRoute::Bmc => {
-                self.usb_switch.set_values(1_u8)?;
-                tokio::fs::write(USB_PORT_POWER, b"disabled").await
-            }
+        // `set_usb_route()` doesn't change which node is selected or its mode, so the currently
+        // selected node's VBUS line (if any) only needs to be protected from the route-switch
+        // glitch, not driven to a new value: `vbus_after: None` restores whatever it was.
+        let vbus_mask = self
+            .current_node
+            .lock()
+            .unwrap()
+            .map_or(0, NodeId::to_bitfield);
+        self.switch_usb(vbus_mask, None, Some(route), None, timing)
+            .await
+    }
+
+    /// Atomically [`Self::select_usb`] `node` into `mode` and then [`Self::set_usb_route`] it,
+    /// using the default [`UsbSwitchTiming`]. Holds `usb_role_lock` across both steps, so callers
+    /// that need a node routed a specific way should prefer this over two separate calls: taking
+    /// and releasing the lock between them leaves a window where the auto-detection task can
+    /// reselect the mux in between.
+    pub async fn select_usb_and_route(
+        &self,
+        node: NodeId,
+        mode: UsbMode,
+        route: UsbRoute,
+    ) -> std::io::Result<()> {
+        self.select_usb_and_route_with_timing(node, mode, route, UsbSwitchTiming::default())
+            .await
+    }
+
+    /// Like [`Self::select_usb_and_route`], but with explicit switching delays.
+    pub async fn select_usb_and_route_with_timing(
+        &self,
+        node: NodeId,
+        mode: UsbMode,
+        route: UsbRoute,
+        timing: UsbSwitchTiming,
+    ) -> std::io::Result<()> {
+        let _guard = self.usb_role_lock.lock().await;
+        self.select_usb_locked(node, mode, timing).await?;
+        self.set_usb_route_locked(route, timing).await
+    }
+
+    /// Ordered, glitch-free transition primitive shared by [`Self::select_usb_with_timing`] and
+    /// [`Self::set_usb_route_with_timing`]: VBUS is dropped on the nodes selected by
+    /// `vbus_mask` first, the analog mux and/or route switch are given `timing.vbus_settle` to
+    /// settle before being reprogrammed, another `timing.mux_settle` window is observed, and
+    /// only then is VBUS for those same nodes restored. `vbus_after` picks what it's restored to:
+    /// `Some(value)` drives the masked bits to `value`, `None` simply restores what they were
+    /// before the call. This guarantees the mux is never reconfigured while VBUS is still live,
+    /// which can back-power a peripheral or brown out a node.
+    async fn switch_usb(
+        &self,
+        vbus_mask: u8,
+        mux: Option<u8>,
+        route: Option<UsbRoute>,
+        vbus_after: Option<u8>,
+        timing: UsbSwitchTiming,
+    ) -> std::io::Result<()> {
+        // A zero mask means this call never touches VBUS (e.g. `set_usb_route()` reprogramming
+        // the route switch for a node that isn't currently selected), so there's nothing to
+        // protect and no reason to pay the settle delay.
+        let before = if vbus_mask != 0 {
+            let before = self.usb_vbus.get_values::<u8>()?;
+            self.usb_vbus.set_values(before & !vbus_mask)?;
+            sleep(timing.vbus_settle).await;
+            before
+        } else {
+            0
+        };
+
+        if let Some(values) = mux {
+            self.usb_mux.set_values(values)?;
         }
+        if let Some(route) = route {
+            self.usb_switch.set_values(match route {
+                UsbRoute::UsbA => 0_u8,
+                UsbRoute::Bmc => 1_u8,
+            })?;
+        }
+
+        sleep(timing.mux_settle).await;
+
+        if let Some(route) = route {
+            let state: &[u8] = match route {
+                UsbRoute::UsbA => b"enabled",
+                UsbRoute::Bmc => b"disabled",
+            };
+            tokio::fs::write(USB_PORT_POWER, state).await?;
+        }
+
+        if vbus_mask != 0 {
+            let restored = vbus_after.unwrap_or(before);
+            self.usb_vbus
+                .set_values((before & !vbus_mask) | (restored & vbus_mask))?;
+        }
+
+        Ok(())
     }
 
     /// Set given nodes into usb boot mode. When powering the node on with this mode enabled, the
@@ -157,8 +444,132 @@ impl PinController {
     }
 
     pub async fn rtl_reset(&self) -> std::io::Result<()> {
+        let _guard = self.usb_role_lock.lock().await;
+        self.rtl_reset_with_hold(Duration::from_secs(1)).await
+    }
+
+    async fn rtl_reset_with_hold(&self, hold: Duration) -> std::io::Result<()> {
         self.rtl_reset.set_values(1u8)?;
-        sleep(Duration::from_secs(1)).await;
+        sleep(hold).await;
         self.rtl_reset.set_values(0u8)
     }
+
+    /// Fully power-cycle the onboard USB hub using the default [`HubPowerCycleTiming`].
+    pub async fn hub_power_cycle(&self) -> std::io::Result<()> {
+        self.hub_power_cycle_with_timing(HubPowerCycleTiming::default())
+            .await
+    }
+
+    /// Recover a wedged onboard USB hub: downstream port power is cut, `RTL_RESET` is asserted
+    /// for `timing.reset_hold` and released, the hub is given `timing.reenumerate_settle` to
+    /// re-enumerate, and downstream power is then restored to each node in turn (staggered by
+    /// `timing.port_power_stagger`) rather than all at once. This lets a wedged hub be recovered
+    /// without yanking power to the whole board.
+    pub async fn hub_power_cycle_with_timing(
+        &self,
+        timing: HubPowerCycleTiming,
+    ) -> std::io::Result<()> {
+        let _guard = self.usb_role_lock.lock().await;
+        debug!("power-cycling onboard USB hub");
+
+        // Remember each node's VBUS state so a node that was left in Host mode (VBUS
+        // intentionally off so the BMC doesn't back-feed it) doesn't come back out of the cycle
+        // with VBUS forced on.
+        let vbus_before = self.usb_vbus.get_values::<u8>()?;
+        self.usb_vbus.set_values(0_u8)?;
+        tokio::fs::write(USB_PORT_POWER, b"disabled").await?;
+
+        self.rtl_reset_with_hold(timing.reset_hold).await?;
+        sleep(timing.reenumerate_settle).await;
+
+        tokio::fs::write(USB_PORT_POWER, b"enabled").await?;
+        let mut vbus = 0_u8;
+        for node in [NodeId::Node1, NodeId::Node2, NodeId::Node3, NodeId::Node4] {
+            vbus |= vbus_before & node.to_bitfield();
+            self.usb_vbus.set_values(vbus)?;
+            sleep(timing.port_power_stagger).await;
+        }
+
+        Ok(())
+    }
+
+    /// Sense the current cable role for `node` from its VBUS-valid and ID/session pins.
+    fn sense_usb_role(&self, node: NodeId) -> std::io::Result<UsbRole> {
+        let idx = node.to_bitfield().trailing_zeros();
+        let vbus_valid = (self.usb_vbus_det.get_values::<u8>()? >> idx) & 1 != 0;
+        let id_grounded = (self.usb_id_det.get_values::<u8>()? >> idx) & 1 == 0;
+        Ok(UsbRole::from_sense(vbus_valid, id_grounded))
+    }
+
+    /// Wait for the next edge event on `lines`. If the backend can't deliver one (e.g. the
+    /// kernel driver doesn't support edge detection on this line), this never resolves so the
+    /// poll-interval branch in [`Self::spawn_usb_role_detection`] is what actually wakes the
+    /// task, making polling a true fallback rather than the primary signal. `warned` tracks
+    /// whether that condition has already been logged, so the fallback doesn't re-warn on every
+    /// poll tick for the lifetime of the task.
+    async fn wait_for_edge(lines: &Lines<Input>, warned: &AtomicBool) {
+        if let Err(error) = lines.read_event().await {
+            if !warned.swap(true, Ordering::Relaxed) {
+                warn!("USB role sense line edge events unavailable, polling instead: {error}");
+            }
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Spawn a background task that senses `node`'s USB cable role and re-drives
+    /// `select_usb()` with the detected mode on every stable transition to `Host` or
+    /// `Peripheral`; a transition to `Disconnected` is reported but does not touch the mux.
+    /// Spurious edges that don't survive the debounce window are ignored. The returned watch
+    /// channel reports the debounced role so callers can observe role changes without polling
+    /// themselves; its initial value is a single undebounced sample taken at spawn time.
+    pub fn spawn_usb_role_detection(self: Arc<Self>, node: NodeId) -> watch::Receiver<UsbRole> {
+        let initial = self.sense_usb_role(node).unwrap_or(UsbRole::Disconnected);
+        let (tx, rx) = watch::channel(initial);
+
+        tokio::spawn(async move {
+            // Start from `Disconnected` regardless of `initial` so the first sensed role is
+            // always driven into hardware, even if it was already present at task start-up.
+            let mut current = UsbRole::Disconnected;
+            loop {
+                tokio::select! {
+                    _ = Self::wait_for_edge(&self.usb_vbus_det, &self.usb_vbus_det_edge_warned) => {}
+                    _ = Self::wait_for_edge(&self.usb_id_det, &self.usb_id_det_edge_warned) => {}
+                    _ = sleep(USB_ROLE_POLL_INTERVAL) => {}
+                }
+
+                let sensed = match self.sense_usb_role(node) {
+                    Ok(role) => role,
+                    Err(error) => {
+                        warn!("failed reading USB role sense lines for {:?}: {error}", node);
+                        continue;
+                    }
+                };
+
+                if sensed == current {
+                    continue;
+                }
+
+                // Debounce: require the new state to still be present after the settle window
+                // before trusting it, otherwise treat it as a spurious edge and keep polling.
+                sleep(USB_ROLE_DEBOUNCE).await;
+                match self.sense_usb_role(node) {
+                    Ok(role) if role == sensed => (),
+                    _ => continue,
+                }
+
+                debug!("detected USB role {:?} for node {:?}", sensed, node);
+                if let Some(mode) = sensed.to_usb_mode() {
+                    if let Err(error) = self.select_usb(node, mode).await {
+                        warn!("failed to auto-switch USB role for node {:?}: {error}", node);
+                        continue;
+                    }
+                }
+
+                current = sensed;
+                let _ = tx.send(sensed);
+            }
+        });
+
+        rx
+    }
 }
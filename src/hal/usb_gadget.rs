@@ -0,0 +1,170 @@
+// Copyright 2023 Turing Machines
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::pin_controller::PinController;
+use super::NodeId;
+use super::UsbMode;
+use super::UsbRoute;
+use anyhow::Context;
+use log::warn;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+
+const CONFIGFS_ROOT: &str = "/sys/kernel/config/usb_gadget";
+const UDC_ROOT: &str = "/sys/class/udc";
+const GADGET_NAME: &str = "bmcd-mass-storage";
+
+// NetChip's vendor ID with the Linux-USB File-backed Storage Gadget product ID, the same pair
+// the in-tree `g_mass_storage` gadget driver defaults to. Left at the kernel's `0x0000` default, a number of
+// host USB stacks refuse to bind a mass-storage driver to the gadget at all.
+const USB_VENDOR_ID: &str = "0x0525";
+const USB_PRODUCT_ID: &str = "0xa4a5";
+
+/// Serves a backing image to a node as a USB mass-storage device via the Linux USB gadget
+/// (configfs) subsystem. Complements [`PinController`]: once a node is routed into
+/// `UsbMode::Flash` with `UsbRoute::Bmc` the BMC is already the USB device end of the link, so
+/// instead of relying on rpiboot this lets the BMC present the image directly as a bootable
+/// virtual USB disk.
+pub struct UsbGadgetManager {
+    pin_controller: Arc<PinController>,
+    gadget_dir: PathBuf,
+}
+
+impl UsbGadgetManager {
+    /// Create a new gadget manager. This does not touch configfs until [`Self::start`] is
+    /// called.
+    pub fn new(pin_controller: Arc<PinController>) -> Self {
+        Self {
+            pin_controller,
+            gadget_dir: Path::new(CONFIGFS_ROOT).join(GADGET_NAME),
+        }
+    }
+
+    /// Route `node` into USB device mode and present `image` to it as a read-only, removable
+    /// mass-storage LUN. Call [`Self::stop`] once flashing completes or is cancelled to tear the
+    /// gadget back down.
+    pub async fn start(&self, node: NodeId, image: &Path) -> anyhow::Result<()> {
+        // Select the node and route USB to the BMC under a single held lock: taking and
+        // releasing it between the two steps would leave a window for the auto-detection task
+        // to reselect the mux before the route switch lands.
+        self.pin_controller
+            .select_usb_and_route(node, UsbMode::Flash, UsbRoute::Bmc)
+            .await
+            .context("failed to route node into USB device mode")?;
+
+        self.create_gadget(image)
+            .await
+            .context("failed to configure USB mass-storage gadget")
+    }
+
+    /// Tear the gadget down, unbinding it from the UDC first. Safe to call even if `start()`
+    /// failed partway through.
+    pub async fn stop(&self) -> anyhow::Result<()> {
+        self.destroy_gadget().await
+    }
+
+    async fn create_gadget(&self, image: &Path) -> anyhow::Result<()> {
+        let strings_dir = self.gadget_dir.join("strings/0x409");
+        let config_dir = self.gadget_dir.join("configs/c.1");
+        let config_strings_dir = config_dir.join("strings/0x409");
+        let function_dir = self.gadget_dir.join("functions/mass_storage.usb0");
+        let lun_dir = function_dir.join("lun.0");
+        let config_link = config_dir.join("mass_storage.usb0");
+
+        fs::create_dir_all(&strings_dir).await?;
+        fs::create_dir_all(&config_strings_dir).await?;
+        fs::create_dir_all(&lun_dir).await?;
+
+        fs::write(self.gadget_dir.join("idVendor"), USB_VENDOR_ID.as_bytes()).await?;
+        fs::write(self.gadget_dir.join("idProduct"), USB_PRODUCT_ID.as_bytes()).await?;
+
+        fs::write(config_strings_dir.join("configuration"), b"flash").await?;
+        fs::write(lun_dir.join("file"), image.to_string_lossy().as_bytes()).await?;
+        fs::write(lun_dir.join("ro"), b"1").await?;
+        fs::write(lun_dir.join("removable"), b"1").await?;
+
+        fs::symlink(&function_dir, &config_link).await?;
+
+        let udc = first_udc().await?;
+        fs::write(self.gadget_dir.join("UDC"), udc.as_bytes()).await?;
+
+        Ok(())
+    }
+
+    async fn destroy_gadget(&self) -> anyhow::Result<()> {
+        let configs_dir = self.gadget_dir.join("configs");
+        let config_dir = configs_dir.join("c.1");
+        let config_link = config_dir.join("mass_storage.usb0");
+        let functions_dir = self.gadget_dir.join("functions");
+        let function_dir = functions_dir.join("mass_storage.usb0");
+
+        // Mirrors the teardown order from Documentation/usb/gadget_configfs.rst: unbind from the
+        // UDC first, then undo `create_gadget()`'s directory creation innermost-first. Every
+        // step is attempted even if an earlier one failed, since `stop()` must also be safe to
+        // call when `start()` only got partway through; failures are logged rather than
+        // silently dropped.
+        warn_on_err("unbind UDC", fs::write(self.gadget_dir.join("UDC"), b"")).await;
+        warn_on_err(
+            "remove function symlink from config",
+            fs::remove_file(&config_link),
+        )
+        .await;
+        warn_on_err(
+            "remove config strings",
+            fs::remove_dir_all(config_dir.join("strings")),
+        )
+        .await;
+        warn_on_err("remove config instance", fs::remove_dir(&config_dir)).await;
+        warn_on_err("remove configs directory", fs::remove_dir(&configs_dir)).await;
+        warn_on_err(
+            "remove mass-storage function",
+            fs::remove_dir_all(&function_dir),
+        )
+        .await;
+        warn_on_err("remove functions directory", fs::remove_dir(&functions_dir)).await;
+        warn_on_err(
+            "remove gadget strings",
+            fs::remove_dir_all(self.gadget_dir.join("strings")),
+        )
+        .await;
+        warn_on_err("remove gadget directory", fs::remove_dir(&self.gadget_dir)).await;
+
+        Ok(())
+    }
+}
+
+/// Await `result`, logging a warning naming `step` if it failed instead of silently dropping the
+/// error. Used by [`UsbGadgetManager::destroy_gadget`], which must best-effort tear down whatever
+/// of the gadget got created even if an earlier step already failed.
+async fn warn_on_err(step: &str, result: impl Future<Output = std::io::Result<()>>) {
+    if let Err(error) = result.await {
+        warn!("failed to {step} while tearing down USB gadget: {error}");
+    }
+}
+
+/// Return the name of the first UDC (USB Device Controller) registered on the system, e.g.
+/// `fe980000.usb`.
+async fn first_udc() -> anyhow::Result<String> {
+    let mut entries = fs::read_dir(UDC_ROOT)
+        .await
+        .context("failed to list UDC devices")?;
+    let entry = entries
+        .next_entry()
+        .await
+        .context("failed to list UDC devices")?
+        .ok_or_else(|| anyhow::anyhow!("no UDC found on this system"))?;
+
+    Ok(entry.file_name().to_string_lossy().into_owned())
+}